@@ -1,27 +1,217 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
 /*!
-A binary semaphore; which are only "one-high", they can hold values of 0-1.
+Blocking semaphores.
+
+[Semaphore] is a binary semaphore; which are only "one-high", they can hold values of 0-1.  For
+the common "limit N concurrent operations" use-case, see [CountingSemaphore], of which [Semaphore]
+is a thin `n == 1` wrapper.
 */
 
 use std::hash::Hash;
-use std::sync::{Arc, Condvar};
-use logwise::perfwarn;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use event_listener::Event;
 
 #[derive(Debug)]
-struct Shared {
-    c: Condvar,
-    m: std::sync::Mutex<bool>
+struct CountingShared {
+    count: AtomicUsize,
+    event: Event
+}
+
+/**
+A counting semaphore, holding a count of available permits.
+
+Unlike [Semaphore], which is capped at 0-1 and panics on double-signal, `CountingSemaphore` allows
+any number of outstanding permits, so it covers the common "limit N concurrent operations"
+use-case without reaching for a different crate.  [Semaphore]'s `signal`/`wait`/`signal_if_needed`
+semantics are the `n == 1` special case of this type.
+
+The uncontended path is lock-free: the count lives in an [AtomicUsize], and waiters park on an
+[Event] only after finding no permit available, re-checking the count once the listener is
+registered to close the lost-wakeup race.
+*/
+#[derive(Debug,Clone)]
+pub struct CountingSemaphore {
+    shared: Arc<CountingShared>
+}
+
+impl PartialEq for CountingSemaphore {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.shared, &other.shared)
+    }
+}
+
+impl Eq for CountingSemaphore {}
+
+impl Hash for CountingSemaphore {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        Arc::as_ptr(&self.shared).hash(state);
+    }
+}
+
+impl CountingSemaphore {
+    /**
+    Creates a new counting semaphore with `n` permits initially available.
+*/
+    pub fn new(n: usize) -> CountingSemaphore {
+        CountingSemaphore {
+            shared: Arc::new(CountingShared {
+                count: AtomicUsize::new(n),
+                event: Event::new()
+            })
+        }
+    }
+}
+
+impl CountingSemaphore {
+    /**
+    Signals (increments) the semaphore, making one more permit available.
+*/
+    pub fn signal(&self) {
+        logwise::trace_sync!("signal");
+        self.shared.count.fetch_add(1, Ordering::SeqCst);
+        //every signal makes exactly one more permit available, so every signal must wake exactly one
+        //waiter; notifying only on a 0 -> 1 transition drops wakeups when more than one waiter is
+        //parked (each later signal would otherwise see `previous != 0` and skip the notify entirely).
+        //`notify(1)` would not do either: it only ensures at least one listener is notified in total,
+        //so it is a no-op while an earlier notification is still pending; `notify_additional` is not.
+        self.shared.event.notify_additional(1);
+    }
+
+    /**
+    Alias for [Self::signal].
+*/
+    pub fn release(&self) {
+        self.signal();
+    }
+
+    /**
+    Waits (decrements) the semaphore, blocking while no permits are available.
+*/
+    pub fn wait(&self) {
+        logwise::trace_sync!("wait");
+        loop {
+            if self.try_wait() {
+                logwise::trace_sync!("finished waiting");
+                return;
+            }
+            //register before rechecking, so a signal arriving between the try_wait above and here
+            //isn't lost
+            let listener = self.shared.event.listen();
+            if self.try_wait() {
+                logwise::trace_sync!("finished waiting");
+                return;
+            }
+            listener.wait();
+        }
+    }
+
+    /**
+    Alias for [Self::wait].
+*/
+    pub fn acquire(&self) {
+        self.wait();
+    }
+
+    /**
+    Checks (and, if possible, decrements) the semaphore without blocking.
+
+    Returns `true` if a permit was available, in which case it has been decremented as if by
+    [Self::wait].  Returns `false` immediately if no permit was available, leaving the count
+    untouched.
+*/
+    pub fn try_wait(&self) -> bool {
+        logwise::trace_sync!("try_wait");
+        let mut current = self.shared.count.load(Ordering::SeqCst);
+        loop {
+            if current == 0 {
+                return false;
+            }
+            match self.shared.count.compare_exchange_weak(current, current - 1, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return true,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /**
+    Waits (decrements) the semaphore, giving up after `dur` has elapsed.
+
+    Returns `true` if a permit was acquired within the deadline, in which case it has been
+    decremented as if by [Self::wait].  Returns `false` on timeout, in which case the count is
+    left untouched.
+*/
+    pub fn wait_timeout(&self, dur: Duration) -> bool {
+        logwise::trace_sync!("wait_timeout");
+        //dur may be large enough that Instant::now() + dur would overflow (e.g. Duration::MAX,
+        //used as a "wait effectively forever but stay interruptible" idiom); treat that case as
+        //never timing out rather than panicking.
+        let deadline = Instant::now().checked_add(dur);
+        loop {
+            if self.try_wait() {
+                logwise::trace_sync!("finished waiting (acquired)");
+                return true;
+            }
+            let listener = self.shared.event.listen();
+            if self.try_wait() {
+                logwise::trace_sync!("finished waiting (acquired)");
+                return true;
+            }
+            let remaining = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(remaining) => remaining,
+                    None => {
+                        logwise::trace_sync!("finished waiting (timed out)");
+                        return false;
+                    }
+                },
+                None => {
+                    listener.wait();
+                    continue;
+                }
+            };
+            if !listener.wait_timeout(remaining) {
+                //either we timed out, or were notified right at the boundary; try once more before
+                //reporting a timeout
+                if self.try_wait() {
+                    logwise::trace_sync!("finished waiting (acquired)");
+                    return true;
+                }
+                logwise::trace_sync!("finished waiting (timed out)");
+                return false;
+            }
+        }
+    }
+
+    /**
+    Unconditionally resets the count to `0`, without blocking.
+
+    Useful for clearing a stale signal before starting a new wait cycle on a semaphore that was
+    left in an unknown state.
+*/
+    pub fn drain(&self) {
+        logwise::trace_sync!("drain");
+        self.shared.count.store(0, Ordering::SeqCst);
+    }
+
+    /**
+    Returns the number of permits currently available, without blocking.
+*/
+    pub fn available_permits(&self) -> usize {
+        self.shared.count.load(Ordering::SeqCst)
+    }
 }
 
 #[derive(Debug,Clone)]
 pub struct Semaphore {
-    shared: Arc<Shared>
+    counting: CountingSemaphore
 }
 
 impl PartialEq for Semaphore {
     fn eq(&self, other: &Self) -> bool {
-        Arc::ptr_eq(&self.shared, &other.shared)
+        self.counting == other.counting
     }
 }
 
@@ -29,7 +219,7 @@ impl Eq for Semaphore {}
 
 impl Hash for Semaphore {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        Arc::as_ptr(&self.shared).hash(state);
+        self.counting.hash(state);
     }
 }
 
@@ -46,17 +236,33 @@ impl Default for Semaphore {
 impl Semaphore {
     /**
     Creates a new semaphore, specifying if it is initially signalled.
+
+    This is a thin wrapper around [CountingSemaphore] with `n` capped at `0` or `1`.
 */
     pub fn new(initially_signaled: bool) -> Semaphore {
         Semaphore {
-            shared: Arc::new(Shared {
-                c: Condvar::new(),
-                m: std::sync::Mutex::new(initially_signaled)
-            })
+            counting: CountingSemaphore::new(initially_signaled as usize)
         }
     }
 }
 
+/**
+An RAII guard returned by [Semaphore::acquire] and [Semaphore::try_acquire].
+
+The semaphore is signalled (via [Semaphore::signal_if_needed]) when the guard is dropped, so a
+panic or early return while the permit is held can't leave the semaphore permanently unsignaled.
+*/
+#[derive(Debug)]
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.signal_if_needed();
+    }
+}
+
 impl Semaphore {
     /**
     Signals (increments) the semaphore.
@@ -64,18 +270,10 @@ impl Semaphore {
     It is a programming error to signal a semaphore that is already signalled.  To do this, use [Self::signal_if_needed].
 */
     pub fn signal(&self) {
-        {
-
-            logwise::trace_sync!("signal");
-            perfwarn!("Semaphore implementation uses mutex", {
-                logwise::trace_sync!("waiting for mutex");
-                let mut guard = self.shared.m.lock().unwrap();
-                logwise::trace_sync!("arrived");
-                assert!(!*guard, "Signalling a semaphore that is already signalled");
-                *guard = true;
-                self.shared.c.notify_one();
-            });
-        }
+        logwise::trace_sync!("signal");
+        let previous = self.counting.shared.count.swap(1, Ordering::SeqCst);
+        assert_eq!(previous, 0, "Signalling a semaphore that is already signalled");
+        self.counting.shared.event.notify(1);
     }
 
     /**
@@ -84,50 +282,213 @@ impl Semaphore {
     Like [Self::signal], but does nothing if the semaphore is already signaled.
 */
     pub fn signal_if_needed(&self) {
-        {
-            logwise::trace_sync!("signal_if_needed");
-            perfwarn!("Semaphore implementation uses mutex", {
-                logwise::trace_sync!("waiting for mutex");
-                let mut guard = self.shared.m.lock().unwrap();
-                logwise::trace_sync!("arrived");
-                *guard = true;
-                self.shared.c.notify_one();
-            });
-
+        logwise::trace_sync!("signal_if_needed");
+        let previous = self.counting.shared.count.swap(1, Ordering::SeqCst);
+        if previous == 0 {
+            self.counting.shared.event.notify(1);
         }
     }
 
     /**Waits (decrements) the semaphore.
     */
     pub fn wait(&self) {
-        logwise::trace_sync!("wait");
-        perfwarn!("Semaphore implementation uses mutex", {
-            logwise::trace_sync!("waiting for mutex");
-            let mtx = self.shared.m.lock().unwrap();
-            logwise::trace_sync!("arrived.  Wait_while...");
-            let mut g = self.shared.c.wait_while(mtx, |guard| {
-                logwise::trace_sync!("...wait_while: {guard}", guard=*guard);
-                !*guard
-                }
-            ).unwrap();
-            logwise::trace_sync!("...finished wait-while.");
+        self.counting.wait();
+    }
+
+    /**
+    Checks (and, if possible, decrements) the semaphore without blocking.
+
+    Returns `true` if the semaphore was signalled, in which case it has been decremented as if by
+    [Self::wait].  Returns `false` immediately if the semaphore was not signalled, leaving it
+    untouched.
+    */
+    pub fn try_wait(&self) -> bool {
+        self.counting.try_wait()
+    }
+
+    /**
+    Waits (decrements) the semaphore, giving up after `dur` has elapsed.
 
-            *g = false;
-        });
-        logwise::trace_sync!("finished waiting");
+    Returns `true` if the semaphore was acquired within the deadline, in which case it has been
+    decremented as if by [Self::wait].  Returns `false` on timeout, in which case the semaphore is
+    left untouched.
+    */
+    pub fn wait_timeout(&self, dur: Duration) -> bool {
+        self.counting.wait_timeout(dur)
+    }
+
+    /**
+    Waits (decrements) the semaphore, returning a [SemaphorePermit] that signals it again on drop.
+    */
+    pub fn acquire(&self) -> SemaphorePermit {
+        self.wait();
+        SemaphorePermit { semaphore: self }
+    }
 
+    /**
+    Like [Self::acquire], but does not block.
 
+    Returns `Some` with a guard if the semaphore was signalled, or `None` immediately otherwise.
+    */
+    pub fn try_acquire(&self) -> Option<SemaphorePermit> {
+        if self.try_wait() {
+            Some(SemaphorePermit { semaphore: self })
+        } else {
+            None
+        }
     }
 
+    /**
+    Unconditionally unsignals the semaphore, without blocking.
 
+    Useful for clearing a stale signal before starting a new wait cycle on a semaphore that was
+    left in an unknown state; unlike [Self::wait], this can never hang.
+*/
+    pub fn drain(&self) {
+        self.counting.drain();
+    }
+
+    /**
+    Returns whether the semaphore is currently signalled, without blocking.
+*/
+    pub fn is_signaled(&self) -> bool {
+        self.counting.available_permits() > 0
+    }
 
 }
 
 #[cfg(test)] mod test {
+    use std::time::Duration;
+
     #[test] fn test_semaphore() {
         logwise::context::Context::reset("test_semaphore");
         let s = super::Semaphore::new(false);
         s.signal();
         s.wait();
     }
-}
\ No newline at end of file
+
+    #[test] fn test_wait_timeout_signaled() {
+        logwise::context::Context::reset("test_wait_timeout_signaled");
+        let s = super::Semaphore::new(false);
+        s.signal();
+        assert!(s.wait_timeout(Duration::from_secs(1)));
+    }
+
+    #[test] fn test_wait_timeout_expires() {
+        logwise::context::Context::reset("test_wait_timeout_expires");
+        let s = super::Semaphore::new(false);
+        assert!(!s.wait_timeout(Duration::from_millis(10)));
+    }
+
+    /**
+    `Duration::MAX` is a realistic "wait effectively forever but stay interruptible" idiom; it
+    must not panic by overflowing `Instant`'s internal representation.
+*/
+    #[test] fn test_wait_timeout_does_not_overflow() {
+        logwise::context::Context::reset("test_wait_timeout_does_not_overflow");
+        let s = super::Semaphore::new(true);
+        assert!(s.wait_timeout(Duration::MAX));
+    }
+
+    #[test] fn test_try_wait() {
+        logwise::context::Context::reset("test_try_wait");
+        let s = super::Semaphore::new(false);
+        assert!(!s.try_wait());
+        s.signal();
+        assert!(s.try_wait());
+        assert!(!s.try_wait());
+    }
+
+    #[test] fn test_acquire() {
+        logwise::context::Context::reset("test_acquire");
+        let s = super::Semaphore::new(true);
+        {
+            let _permit = s.acquire();
+            assert!(!s.try_wait());
+        }
+        //dropping the permit re-signals the semaphore
+        assert!(s.try_wait());
+    }
+
+    #[test] fn test_try_acquire() {
+        logwise::context::Context::reset("test_try_acquire");
+        let s = super::Semaphore::new(false);
+        assert!(s.try_acquire().is_none());
+        s.signal();
+        let permit = s.try_acquire();
+        assert!(permit.is_some());
+        assert!(s.try_acquire().is_none());
+        drop(permit);
+        assert!(s.try_wait());
+    }
+
+    #[test] fn test_counting_semaphore() {
+        logwise::context::Context::reset("test_counting_semaphore");
+        let s = super::CountingSemaphore::new(2);
+        assert!(s.try_wait());
+        assert!(s.try_wait());
+        assert!(!s.try_wait());
+        s.signal();
+        s.wait();
+    }
+
+    /**
+    Regression test: every `signal()` must wake a waiter, not just ones landing on a `0 -> 1`
+    count transition, or a waiter parked behind other waiters can be left permanently asleep.
+*/
+    #[test] fn test_counting_semaphore_wakes_all_waiters() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        use std::thread;
+
+        logwise::context::Context::reset("test_counting_semaphore_wakes_all_waiters");
+        const WAITERS: usize = 4;
+        let s = super::CountingSemaphore::new(0);
+        let completed = Arc::new(AtomicUsize::new(0));
+
+        let handles: Vec<_> = (0..WAITERS).map(|_| {
+            let s = s.clone();
+            let completed = completed.clone();
+            thread::spawn(move || {
+                s.wait();
+                completed.fetch_add(1, Ordering::SeqCst);
+            })
+        }).collect();
+
+        //give the waiters a chance to register their listeners before signalling, so the
+        //test exercises the register-then-recheck path rather than the try_wait fast path
+        thread::sleep(Duration::from_millis(50));
+
+        for _ in 0..WAITERS {
+            s.signal();
+        }
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(completed.load(Ordering::SeqCst), WAITERS);
+    }
+
+    #[test] fn test_drain_and_is_signaled() {
+        logwise::context::Context::reset("test_drain_and_is_signaled");
+        let s = super::Semaphore::new(false);
+        assert!(!s.is_signaled());
+        s.signal();
+        assert!(s.is_signaled());
+        s.drain();
+        assert!(!s.is_signaled());
+        //draining an already-unsignaled semaphore is a no-op, not an error
+        s.drain();
+        assert!(!s.is_signaled());
+    }
+
+    #[test] fn test_counting_drain_and_available_permits() {
+        logwise::context::Context::reset("test_counting_drain_and_available_permits");
+        let s = super::CountingSemaphore::new(3);
+        assert_eq!(s.available_permits(), 3);
+        s.drain();
+        assert_eq!(s.available_permits(), 0);
+        assert!(!s.try_wait());
+    }
+}